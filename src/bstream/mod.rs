@@ -10,13 +10,17 @@
 
 //! Bstream module.
 //! This is a blocking stream designed to block on read/write until
+//! a complete message has been sent or received.
 
 
 use std::result::Result;
 use std::net::{TcpStream, Shutdown};
-use std::io::{Read, Write, Error};
+use std::io::Error;
 
-use super::readbuffer::ReadBuffer;
+use frame::{Codec, FrameLen, LengthPrefixCodec};
+use reader::Reader;
+use writer::Writer;
+use reconnect::{ReconnectPolicy, ReconnectState};
 
 
 /// Represents the result of attempting a read on the underlying file descriptor
@@ -26,116 +30,118 @@ pub type ReadResult = Result<Vec<u8>, Error>;
 pub type WriteResult = Result<(), Error>;
 
 
-/// States the current stream can be in
-#[derive(PartialEq, Clone)]
-enum ReadState {
-    /// Currently reading the payload length
-    PayloadLen,
-    /// Currently reading the payload
-    Payload
-}
-
-pub struct Bstream {
-    /// Current state
-    state: ReadState,
+/// A blocking, length-prefixed stream. Thin wrapper around a
+/// `TcpStream`, parameterized over a `Codec` that determines how
+/// messages are framed on the wire.
+pub struct Bstream<C: Codec = LengthPrefixCodec> {
     /// Underlying std::net::TcpStream
     stream: TcpStream,
-    /// Message buffer
-    buffer: ReadBuffer
+    /// Drives reads through the codec's decode state machine
+    reader: Reader<C>,
+    /// Drives writes through the codec's encode step
+    writer: Writer<C>,
+    /// Codec, kept around so a reconnect can rebuild `reader` with fresh
+    /// decode state
+    codec: C,
+    /// Connect target and retry policy shared with `Nbstream`
+    reconnect: ReconnectState,
+    /// The buffer of a `write` that failed because the connection was
+    /// gone; that same `write` replays it once its reconnect succeeds.
+    /// A reconnect triggered by `read` does not replay it.
+    last_write: Option<Vec<u8>>
 }
 
+impl Bstream<LengthPrefixCodec> {
+
+    /// Returns a new Bstream that frames messages with a 2-byte
+    /// big-endian length prefix
+    pub fn new(stream: TcpStream) -> Bstream<LengthPrefixCodec> {
+        Bstream::with_frame_len(stream, FrameLen::default())
+    }
+
+    /// Returns a new Bstream that frames messages according to
+    /// `frame_len`, allowing payloads larger than 65,535 bytes
+    pub fn with_frame_len(stream: TcpStream, frame_len: FrameLen) -> Bstream<LengthPrefixCodec> {
+        Bstream::with_codec(stream, LengthPrefixCodec::new(frame_len))
+    }
+}
 
-impl Bstream {
+impl<C: Codec + Clone> Bstream<C> {
+
+    /// Returns a new Bstream framed by a custom `Codec`, for callers
+    /// that need a delimiter other than the built-in length prefix
+    pub fn with_codec(stream: TcpStream, codec: C) -> Bstream<C> {
+        // Best-effort: a stream that can't report its peer address still
+        // works fine, it just can't reconnect later.
+        let connect_addr = stream.peer_addr().ok();
 
-    /// Returns a new Bstream
-    pub fn new(stream: TcpStream) -> Bstream {
         Bstream {
-            state: ReadState::PayloadLen,
             stream: stream,
-            buffer: ReadBuffer::new()
+            reader: Reader::new(codec.clone()),
+            writer: Writer::new(codec.clone()),
+            codec: codec,
+            reconnect: ReconnectState::new(connect_addr),
+            last_write: None
         }
     }
 
+    /// Enables transparent reconnection: if a read or write indicates
+    /// the peer is gone, the stream re-connects to its original target
+    /// according to `policy` instead of surfacing the error
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect.set_policy(policy);
+    }
+
     /// Performs a blocking read and returns when a complete message
-    /// has been returned, or an error has occured
+    /// has been returned, or an error has occured. If a reconnect
+    /// policy is set and the connection was found to be gone, the
+    /// stream reconnects and the read is retried once against the new
+    /// connection.
     pub fn read(&mut self) -> ReadResult {
-        loop {
-            // Create a buffer for this specific read iteration
-            let count = self.buffer.remaining();
-            let mut buffer = Vec::<u8>::with_capacity(count as usize);
-            unsafe { buffer.set_len(count as usize); }
-
-            let result = self.stream.read(&mut buffer[..]);
-            if result.is_err() {
-                return Err(result.unwrap_err());
-            }
-
-            let num_read = result.unwrap();
-            for x in 0..num_read {
-                self.buffer.push(buffer[x]);
-            }
-
-            if self.buffer.remaining() == 0 {
-                if self.state == ReadState::PayloadLen {
-                    let mut index = 0;
-                    for byte in self.buffer.current_buffer().iter() {
-                        println!("byte {}: {}", index, byte);
-                        index += 1;
-                    }
-
-                    self.buffer.calc_payload_len();
-                    let p_len = self.buffer.payload_len();
-                    self.buffer.set_capacity(p_len);
-                    self.state = ReadState::Payload;
-                } else { // Payload completely read
-                    let mut index = 0;
-                    for byte in self.buffer.current_buffer().iter() {
-                        println!("byte {}: {}", index, byte);
-                        index += 1;
+        match self.reader.read(&mut self.stream) {
+            Ok(msg) => Ok(msg),
+            Err(e) => {
+                if self.should_reconnect(&e) {
+                    match self.reconnect() {
+                        Ok(()) => self.reader.read(&mut self.stream),
+                        Err(reconnect_err) => Err(reconnect_err)
                     }
-
-                    self.buffer.reset();
-                    self.state = ReadState::PayloadLen;
-                    break;
+                } else {
+                    Err(e)
                 }
             }
         }
-        let mut buffer = self.buffer.drain_queue();
-
-        // This should always be .len() of 1
-        // if it isn't - we're doing some bad stuff in here
-        if buffer.len() != 1 {
-            panic!("Error - Bstream.read() - Internal buffer was not equal to one...?")
-        }
-
-        match buffer.pop() {
-            Some(buf) => Ok(buf),
-            None => unimplemented!()
-        }
     }
 
-    /// Performs a blocking write operation and returns the complete buffer has
-    /// been written, or an error has occured
+    /// Performs a blocking write operation and returns once the
+    /// complete buffer has been written, or an error has occured. If a
+    /// reconnect policy is set and the connection was found to be gone,
+    /// the stream reconnects and replays this write against the new
+    /// connection.
     pub fn write(&mut self, buffer: &Vec<u8>) -> WriteResult {
-        let mut plen_buf = [0u8; 2];
-        let plen = buffer.len() as u16;
-        plen_buf[0] = (plen >> 8) as u8;
-        plen_buf[1] = plen as u8;
-
-        let mut n_buffer = Vec::<u8>::with_capacity(buffer.len() + 2);
-        n_buffer.push(plen_buf[0]);
-        n_buffer.push(plen_buf[1]);
-
-        for x in 0..buffer.len() {
-            n_buffer.push(buffer[x]);
-        }
-
-        match self.stream.write_all(&n_buffer[..]) {
+        match self.writer.write(&mut self.stream, &buffer[..]) {
             Ok(()) => {
-                let _ = self.stream.flush();
+                self.last_write = None;
                 Ok(())
             }
-            Err(e) => Err(e)
+            Err(e) => {
+                if self.should_reconnect(&e) {
+                    self.last_write = Some(buffer.clone());
+                    match self.reconnect() {
+                        Ok(()) => {
+                            let replay = self.last_write.take().unwrap();
+                            let result = self.writer.write(&mut self.stream, &replay[..]);
+                            if result.is_ok() {
+                                self.last_write = None;
+                            }
+                            result
+                        }
+                        Err(reconnect_err) => Err(reconnect_err)
+                    }
+                } else {
+                    Err(e)
+                }
+            }
         }
     }
 
@@ -146,14 +152,74 @@ impl Bstream {
             panic!("Error shutting down stream: {}", result.unwrap_err())
         }
     }
+
+    /// True if `err` indicates the connection is gone and a reconnect
+    /// policy has been configured
+    fn should_reconnect(&self, err: &Error) -> bool {
+        self.reconnect.should_reconnect(err)
+    }
+
+    /// Re-establishes the connection via `reconnect`, replacing `stream`
+    /// and `reader` on success and discarding any partial header or
+    /// payload bytes left over from the dropped connection.
+    fn reconnect(&mut self) -> Result<(), Error> {
+        match self.reconnect.reconnect() {
+            Ok(stream) => {
+                self.stream = stream;
+                self.reader = Reader::new(self.codec.clone());
+                Ok(())
+            }
+            Err(e) => Err(e)
+        }
+    }
 }
 
-impl Clone for Bstream {
-    fn clone(&self) -> Bstream {
+impl<C: Codec + Clone> Clone for Bstream<C> {
+    fn clone(&self) -> Bstream<C> {
         Bstream {
-            state: self.state.clone(),
             stream: self.stream.try_clone().unwrap(),
-            buffer: self.buffer.clone()
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+            codec: self.codec.clone(),
+            reconnect: self.reconnect,
+            last_write: self.last_write.clone()
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use reconnect::ReconnectPolicy;
+
+    use super::*;
+
+    #[test]
+    fn read_reconnects_after_peer_drops_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (first, _) = listener.accept().unwrap();
+            drop(first);
+
+            let (mut second, _) = listener.accept().unwrap();
+            second.write_all(&[0, 5]).unwrap();
+            second.write_all(b"hello").unwrap();
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+        let mut bstream = Bstream::new(client);
+        bstream.set_reconnect_policy(ReconnectPolicy::new(5, Duration::from_millis(10)));
+
+        let msg = bstream.read().expect("stream should reconnect and complete the read");
+        assert_eq!(msg, b"hello".to_vec());
+
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+
+//! Defines the width and encoding of the length prefix written before
+//! each frame's payload, and the `Codec` trait framing is built on.
+
+
+use std::io::{Error, ErrorKind};
+
+use readbuffer::ReadBuffer;
+
+
+/// Default cap on a single decoded frame, in bytes. Guards against a
+/// peer (or corrupted stream) claiming an unreasonably large length and
+/// forcing a multi-gigabyte allocation for a trickle of actual bytes.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+
+/// Decodes and encodes frames for a stream. Implement this to plug in a
+/// custom delimiter (newline-delimited, fixed-size blocks, a bespoke
+/// header) in place of the built-in length-prefix scheme.
+pub trait Codec {
+    /// Returns a fresh `ReadBuffer` sized for this codec's first frame
+    fn new_buffer(&self) -> ReadBuffer;
+
+    /// Feeds the bytes accumulated in `buf` through the codec's state
+    /// machine. Returns `Ok(Some(msg))` once a complete message has
+    /// been decoded, leaving `buf` ready to accumulate the next frame,
+    /// or `Err` if the frame in progress is invalid (for example, a
+    /// decoded length beyond a configured maximum).
+    fn decode(&mut self, buf: &mut ReadBuffer) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Encodes `msg` into the bytes that should be written to the wire
+    fn encode(&self, msg: &[u8]) -> Vec<u8>;
+}
+
+
+/// Phase a `LengthPrefixCodec` is currently decoding
+#[derive(Clone, Copy, PartialEq)]
+enum FrameState {
+    /// Currently reading the length header
+    Header,
+    /// Currently reading the payload
+    Payload
+}
+
+/// The crate's original framing: a `FrameLen` header followed by that
+/// many bytes of payload
+#[derive(Clone, Copy)]
+pub struct LengthPrefixCodec {
+    frame_len: FrameLen,
+    state: FrameState,
+    /// Largest payload length this codec will honor. `0` means
+    /// unlimited.
+    max_frame_len: usize
+}
+
+impl LengthPrefixCodec {
+
+    /// Returns a new LengthPrefixCodec that frames messages according
+    /// to `frame_len`, rejecting any payload larger than
+    /// `DEFAULT_MAX_FRAME_LEN`
+    pub fn new(frame_len: FrameLen) -> LengthPrefixCodec {
+        LengthPrefixCodec::with_max_frame_len(frame_len, DEFAULT_MAX_FRAME_LEN)
+    }
+
+    /// Returns a new LengthPrefixCodec that frames messages according
+    /// to `frame_len`, rejecting any payload larger than
+    /// `max_frame_len` bytes. Pass `0` for no limit.
+    pub fn with_max_frame_len(frame_len: FrameLen, max_frame_len: usize) -> LengthPrefixCodec {
+        LengthPrefixCodec {
+            frame_len: frame_len,
+            state: FrameState::Header,
+            max_frame_len: max_frame_len
+        }
+    }
+}
+
+impl Default for LengthPrefixCodec {
+    fn default() -> LengthPrefixCodec {
+        LengthPrefixCodec::new(FrameLen::default())
+    }
+}
+
+impl Codec for LengthPrefixCodec {
+    fn new_buffer(&self) -> ReadBuffer {
+        ReadBuffer::new(self.frame_len)
+    }
+
+    fn decode(&mut self, buf: &mut ReadBuffer) -> Result<Option<Vec<u8>>, Error> {
+        if buf.remaining() != 0 {
+            return Ok(None);
+        }
+
+        match self.state {
+            FrameState::Header => {
+                buf.calc_payload_len();
+                let p_len = buf.payload_len();
+
+                if self.max_frame_len != 0 && p_len > self.max_frame_len {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("frame length {} exceeds max_frame_len {}", p_len, self.max_frame_len)));
+                }
+
+                buf.set_capacity(p_len);
+                self.state = FrameState::Payload;
+                Ok(None)
+            }
+            FrameState::Payload => {
+                buf.reset();
+                self.state = FrameState::Header;
+                Ok(buf.drain_queue().pop())
+            }
+        }
+    }
+
+    fn encode(&self, msg: &[u8]) -> Vec<u8> {
+        let mut header = self.frame_len.encode(msg.len());
+        header.extend_from_slice(msg);
+        header
+    }
+}
+
+
+/// Determines how many bytes (and in what encoding) a stream's length
+/// prefix occupies, and therefore how large a single message may be.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameLen {
+    /// 2-byte big-endian length prefix. Caps a single payload at 65,535
+    /// bytes. This is the historical, default behavior.
+    U16,
+    /// 4-byte big-endian length prefix
+    U32,
+    /// 8-byte big-endian length prefix, modeled on the way tarpc
+    /// prefixes messages with a `u64` length
+    U64,
+    /// LEB128-style variable length prefix. Each byte contributes 7 bits
+    /// of the length, with the high bit set on every byte but the last
+    Varint
+}
+
+impl FrameLen {
+    /// Minimum number of header bytes needed before the payload length
+    /// can be determined. For `Varint` this is only the first byte;
+    /// `ReadBuffer` grows the target by one for every continuation byte
+    /// it encounters.
+    pub fn min_header_len(&self) -> usize {
+        match *self {
+            FrameLen::U16 => 2,
+            FrameLen::U32 => 4,
+            FrameLen::U64 => 8,
+            FrameLen::Varint => 1
+        }
+    }
+
+    /// Encodes `len` as a header according to this frame length scheme
+    pub fn encode(&self, len: usize) -> Vec<u8> {
+        match *self {
+            FrameLen::U16 => {
+                let len = len as u16;
+                vec![(len >> 8) as u8, len as u8]
+            }
+            FrameLen::U32 => {
+                let len = len as u32;
+                vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]
+            }
+            FrameLen::U64 => {
+                let len = len as u64;
+                (0..8).rev().map(|shift| (len >> (shift * 8)) as u8).collect()
+            }
+            FrameLen::Varint => {
+                let mut len = len as u64;
+                let mut bytes = Vec::new();
+                loop {
+                    let mut byte = (len & 0x7f) as u8;
+                    len >>= 7;
+                    if len != 0 {
+                        byte |= 0x80;
+                    }
+                    bytes.push(byte);
+                    if len == 0 {
+                        break;
+                    }
+                }
+                bytes
+            }
+        }
+    }
+
+    /// Decodes a complete header into a payload length. For `Varint`,
+    /// `header` must already contain a terminating byte (high bit clear)
+    pub fn decode(&self, header: &[u8]) -> usize {
+        match *self {
+            FrameLen::U16 | FrameLen::U32 | FrameLen::U64 => {
+                header.iter().fold(0usize, |acc, &byte| (acc << 8) | byte as usize)
+            }
+            FrameLen::Varint => {
+                let mut result = 0u64;
+                let mut shift = 0;
+                for &byte in header {
+                    result |= ((byte & 0x7f) as u64) << shift;
+                    shift += 7;
+                }
+                result as usize
+            }
+        }
+    }
+
+    /// Returns true if `byte` carries the varint continuation bit
+    pub fn has_continuation(byte: u8) -> bool {
+        byte & 0x80 != 0
+    }
+}
+
+impl Default for FrameLen {
+    fn default() -> FrameLen {
+        FrameLen::U16
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_len_round_trip() {
+        let cases = [
+            (FrameLen::U16, 0usize),
+            (FrameLen::U16, 65_535),
+            (FrameLen::U32, 1),
+            (FrameLen::U32, 4_294_967_295),
+            (FrameLen::U64, 1),
+            (FrameLen::U64, 1_000_000_000_000),
+            (FrameLen::Varint, 0),
+            (FrameLen::Varint, 127),
+            (FrameLen::Varint, 128),
+            (FrameLen::Varint, 1_000_000)
+        ];
+
+        for &(frame_len, len) in cases.iter() {
+            let header = frame_len.encode(len);
+            assert_eq!(frame_len.decode(&header), len);
+        }
+    }
+
+    #[test]
+    fn varint_header_len_grows_with_continuation_bytes() {
+        assert_eq!(FrameLen::Varint.encode(127).len(), 1);
+        assert_eq!(FrameLen::Varint.encode(128).len(), 2);
+    }
+}
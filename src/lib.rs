@@ -15,14 +15,22 @@ extern crate openssl;
 
 use std::io::Error;
 
-pub use plain::*;
-pub use socket::*;
-pub use secure::*;
+pub use frame::{FrameLen, Codec, LengthPrefixCodec};
+pub use bstream::Bstream;
+pub use nbstream::Nbstream;
+pub use outbound::OutboundQueue;
+pub use ratelimit::RateLimited;
+pub use reconnect::ReconnectPolicy;
 
 mod frame;
-mod socket;
-mod plain;
-mod secure;
+mod readbuffer;
+mod reader;
+mod writer;
+mod bstream;
+mod nbstream;
+mod outbound;
+mod ratelimit;
+mod reconnect;
 
 
 pub trait Blocking {
@@ -0,0 +1,217 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the
+// terms of the Mozilla Public License, v.
+// 2.0. If a copy of the MPL was not
+// distributed with this file, You can
+// obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+//! Nbstream module.
+//! This is a non-blocking stream: reads and writes never block,
+//! returning `ErrorKind::WouldBlock` when the socket isn't ready.
+
+
+use std::net::{TcpStream, Shutdown};
+use std::io::{Error, ErrorKind};
+
+use frame::{Codec, FrameLen, LengthPrefixCodec};
+use reader::Reader;
+use outbound::{
+    OutboundQueue,
+    DEFAULT_MAX_QUEUED_BUFFERS,
+    DEFAULT_BACKPRESSURE_BYTES,
+    DEFAULT_COALESCE_BYTES
+};
+use reconnect::{ReconnectPolicy, ReconnectState};
+use NonBlocking;
+
+
+/// A non-blocking, length-prefixed stream. Outbound frames are queued
+/// and flushed opportunistically rather than written straight through,
+/// so many small writes can be coalesced and a slow peer can't make
+/// `nb_send` block.
+pub struct Nbstream<C: Codec = LengthPrefixCodec> {
+    /// Underlying std::net::TcpStream, set to non-blocking mode
+    stream: TcpStream,
+    /// Drives reads through the codec's decode state machine
+    reader: Reader<C>,
+    /// Encodes outbound messages before they're queued
+    codec: C,
+    /// Frames waiting to be flushed to the socket. Doubles as the
+    /// in-flight replay buffer across a reconnect: anything still
+    /// queued here simply keeps being flushed against the new
+    /// connection.
+    outbound: OutboundQueue,
+    /// Connect target and retry policy shared with `Bstream`
+    reconnect: ReconnectState
+}
+
+impl Nbstream<LengthPrefixCodec> {
+
+    /// Returns a new Nbstream that frames messages with a 2-byte
+    /// big-endian length prefix
+    pub fn new(stream: TcpStream) -> Nbstream<LengthPrefixCodec> {
+        Nbstream::with_frame_len(stream, FrameLen::default())
+    }
+
+    /// Returns a new Nbstream that frames messages according to
+    /// `frame_len`
+    pub fn with_frame_len(stream: TcpStream, frame_len: FrameLen) -> Nbstream<LengthPrefixCodec> {
+        Nbstream::with_codec(stream, LengthPrefixCodec::new(frame_len))
+    }
+}
+
+impl<C: Codec + Clone> Nbstream<C> {
+
+    /// Returns a new Nbstream framed by a custom `Codec`, using the
+    /// default outbound queue limits
+    pub fn with_codec(stream: TcpStream, codec: C) -> Nbstream<C> {
+        Nbstream::with_limits(
+            stream,
+            codec,
+            DEFAULT_MAX_QUEUED_BUFFERS,
+            DEFAULT_BACKPRESSURE_BYTES,
+            DEFAULT_COALESCE_BYTES)
+    }
+
+    /// Returns a new Nbstream with explicit outbound queue limits:
+    /// `max_queued_buffers` caps how many pending frames may queue,
+    /// `backpressure_bytes` is the total queued size at or above which
+    /// `nb_send` starts returning `WouldBlock`, and `coalesce_bytes` is
+    /// the size under which pending frames are concatenated before
+    /// being flushed.
+    pub fn with_limits(stream: TcpStream,
+                        codec: C,
+                        max_queued_buffers: usize,
+                        backpressure_bytes: usize,
+                        coalesce_bytes: usize) -> Nbstream<C> {
+        stream.set_nonblocking(true).expect("Error setting stream to non-blocking");
+
+        // Best-effort: a stream that can't report its peer address still
+        // works fine, it just can't reconnect later.
+        let connect_addr = stream.peer_addr().ok();
+
+        Nbstream {
+            reader: Reader::new(codec.clone()),
+            codec: codec,
+            outbound: OutboundQueue::new(max_queued_buffers, backpressure_bytes, coalesce_bytes),
+            stream: stream,
+            reconnect: ReconnectState::new(connect_addr)
+        }
+    }
+
+    /// Enables transparent reconnection: if a read or write indicates
+    /// the peer is gone, the stream re-connects to its original target
+    /// according to `policy` instead of surfacing the error
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect.set_policy(policy);
+    }
+
+    /// Drains as much of the outbound queue as the socket will
+    /// currently accept. Returns the number of bytes still queued
+    /// afterward, so event-loop callers know whether to keep
+    /// registering for write-readiness.
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        match self.outbound.try_flush(&mut self.stream) {
+            Ok(remaining) => Ok(remaining),
+            Err(e) => {
+                if self.should_reconnect(&e) {
+                    match self.reconnect() {
+                        Ok(()) => self.outbound.try_flush(&mut self.stream),
+                        Err(reconnect_err) => Err(reconnect_err)
+                    }
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Alias for `flush`, matching the non-blocking `try_*` naming
+    pub fn try_flush(&mut self) -> Result<usize, Error> {
+        self.flush()
+    }
+
+    /// Shuts down the connection
+    pub fn shutdown(&self) {
+        let result = self.stream.shutdown(Shutdown::Both);
+        if result.is_err() {
+            panic!("Error shutting down stream: {}", result.unwrap_err())
+        }
+    }
+
+    /// True if `err` indicates the connection is gone and a reconnect
+    /// policy has been configured
+    fn should_reconnect(&self, err: &Error) -> bool {
+        self.reconnect.should_reconnect(err)
+    }
+
+    /// Re-establishes the connection via `reconnect`, replacing `stream`
+    /// and `reader` on success and discarding any partial header or
+    /// payload bytes left over from the dropped connection. Anything
+    /// still sitting in `outbound` is left in place, so it gets flushed
+    /// to the new connection on the next send or flush.
+    fn reconnect(&mut self) -> Result<(), Error> {
+        match self.reconnect.reconnect() {
+            Ok(stream) => {
+                stream.set_nonblocking(true).expect("Error setting stream to non-blocking");
+                self.stream = stream;
+                self.reader = Reader::new(self.codec.clone());
+                Ok(())
+            }
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl<C: Codec + Clone> NonBlocking for Nbstream<C> {
+
+    /// Drains as many complete messages as are currently available
+    /// without blocking. An empty Vec means no full message has
+    /// arrived yet, not an error. If a reconnect policy is set and the
+    /// connection was found to be gone, the stream reconnects and
+    /// keeps draining against the new connection.
+    fn nb_recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut msgs = Vec::new();
+
+        loop {
+            match self.reader.read(&mut self.stream) {
+                Ok(msg) => msgs.push(msg),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    if self.should_reconnect(&e) {
+                        match self.reconnect() {
+                            Ok(()) => continue,
+                            Err(reconnect_err) => return Err(reconnect_err)
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(msgs)
+    }
+
+    /// Encodes `buf` and queues it for send, then opportunistically
+    /// flushes the outbound queue. Returns a `WouldBlock` error without
+    /// queuing if the outbound queue is already full. If a reconnect
+    /// policy is set and flushing finds the connection gone, the stream
+    /// reconnects and keeps flushing the queue (which still holds
+    /// `buf`) against the new connection.
+    fn nb_send(&mut self, buf: &[u8]) -> Result<(), Error> {
+        let encoded = self.codec.encode(buf);
+
+        match self.outbound.enqueue(encoded) {
+            Ok(()) => {}
+            Err(e) => return Err(e)
+        }
+
+        match self.flush() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+}
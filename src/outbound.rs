@@ -0,0 +1,222 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+
+//! Outbound write buffering for non-blocking sockets: backpressure once
+//! too much has queued up, and coalescing of small writes into fewer
+//! syscalls.
+
+
+use std::collections::VecDeque;
+use std::io::{Write, Error, ErrorKind};
+
+
+/// Default maximum number of buffers allowed to queue before `enqueue`
+/// starts refusing further writes
+pub const DEFAULT_MAX_QUEUED_BUFFERS: usize = 1024;
+
+/// Default byte-count backpressure threshold, in bytes
+pub const DEFAULT_BACKPRESSURE_BYTES: usize = 64 * 1024;
+
+/// Default aggregation threshold, in bytes: pending buffers smaller
+/// than this are concatenated into a single allocation before a flush
+pub const DEFAULT_COALESCE_BYTES: usize = 1024;
+
+
+/// Buffers outbound frames for a non-blocking socket
+pub struct OutboundQueue {
+    pending: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    max_queued_buffers: usize,
+    backpressure_bytes: usize,
+    coalesce_bytes: usize
+}
+
+impl OutboundQueue {
+
+    /// Returns a new, empty OutboundQueue
+    pub fn new(max_queued_buffers: usize,
+               backpressure_bytes: usize,
+               coalesce_bytes: usize) -> OutboundQueue {
+        OutboundQueue {
+            pending: VecDeque::new(),
+            queued_bytes: 0,
+            max_queued_buffers: max_queued_buffers,
+            backpressure_bytes: backpressure_bytes,
+            coalesce_bytes: coalesce_bytes
+        }
+    }
+
+    /// Number of bytes currently queued, awaiting a flush
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// True if there is nothing left to flush
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues `buf` for a future flush. Returns a `WouldBlock` error,
+    /// without queuing, once too many buffers or too many bytes are
+    /// already pending, so the caller knows to stop enqueuing.
+    pub fn enqueue(&mut self, buf: Vec<u8>) -> Result<(), Error> {
+        if self.pending.len() >= self.max_queued_buffers
+            || self.queued_bytes >= self.backpressure_bytes
+        {
+            return Err(Error::new(ErrorKind::WouldBlock, "outbound queue full"));
+        }
+
+        self.queued_bytes += buf.len();
+        self.pending.push_back(buf);
+        Ok(())
+    }
+
+    /// Writes as much of the queue as `dst` will currently accept,
+    /// coalescing pending buffers smaller than the configured threshold
+    /// into a single allocation first. Returns the number of bytes still
+    /// queued after the attempt.
+    pub fn try_flush<W: Write>(&mut self, dst: &mut W) -> Result<usize, Error> {
+        while !self.pending.is_empty() {
+            let chunk = self.next_chunk();
+            let chunk_len = chunk.len();
+
+            let result = dst.write(&chunk[..]);
+            match result {
+                Ok(n) if n == chunk_len => {
+                    self.queued_bytes -= n;
+                }
+                Ok(n) => {
+                    self.queued_bytes -= n;
+                    self.pending.push_front(chunk[n..].to_vec());
+                    break;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.pending.push_front(chunk);
+                    break;
+                }
+                Err(e) => {
+                    self.pending.push_front(chunk);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(self.queued_bytes)
+    }
+
+    /// Alias for `try_flush`, for callers that prefer the plain name
+    pub fn flush<W: Write>(&mut self, dst: &mut W) -> Result<usize, Error> {
+        self.try_flush(dst)
+    }
+
+    /// Pops the next buffer to write, merging in however many
+    /// subsequent small buffers still fit under the coalesce threshold
+    fn next_chunk(&mut self) -> Vec<u8> {
+        let mut chunk = self.pending.pop_front().unwrap();
+
+        while chunk.len() < self.coalesce_bytes {
+            let should_merge = match self.pending.front() {
+                Some(next) => chunk.len() + next.len() <= self.coalesce_bytes,
+                None => false
+            };
+
+            if !should_merge {
+                break;
+            }
+
+            let next = self.pending.pop_front().unwrap();
+            chunk.extend_from_slice(&next[..]);
+        }
+
+        chunk
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Write, Error, ErrorKind};
+
+    use super::*;
+
+    struct CountingWriter {
+        buf: Vec<u8>,
+        writes: usize
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+            self.buf.extend_from_slice(data);
+            self.writes += 1;
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    struct WouldBlockWriter;
+
+    impl Write for WouldBlockWriter {
+        fn write(&mut self, _data: &[u8]) -> Result<usize, Error> {
+            Err(Error::new(ErrorKind::WouldBlock, "would block"))
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn try_flush_coalesces_small_buffers_into_one_write() {
+        let mut queue = OutboundQueue::new(DEFAULT_MAX_QUEUED_BUFFERS, DEFAULT_BACKPRESSURE_BYTES, 1024);
+        queue.enqueue(vec![1, 2, 3]).unwrap();
+        queue.enqueue(vec![4, 5, 6]).unwrap();
+        queue.enqueue(vec![7, 8, 9]).unwrap();
+
+        let mut dst = CountingWriter { buf: Vec::new(), writes: 0 };
+        let remaining = queue.try_flush(&mut dst).unwrap();
+
+        assert_eq!(remaining, 0);
+        assert_eq!(dst.writes, 1);
+        assert_eq!(dst.buf, vec![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn enqueue_rejects_once_backpressure_bytes_exceeded() {
+        let mut queue = OutboundQueue::new(DEFAULT_MAX_QUEUED_BUFFERS, 4, DEFAULT_COALESCE_BYTES);
+        queue.enqueue(vec![0u8; 4]).unwrap();
+
+        let result = queue.enqueue(vec![0u8; 1]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn enqueue_rejects_once_max_queued_buffers_exceeded() {
+        let mut queue = OutboundQueue::new(1, DEFAULT_BACKPRESSURE_BYTES, DEFAULT_COALESCE_BYTES);
+        queue.enqueue(vec![1]).unwrap();
+
+        let result = queue.enqueue(vec![2]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn try_flush_leaves_buffer_queued_on_would_block() {
+        let mut queue = OutboundQueue::new(DEFAULT_MAX_QUEUED_BUFFERS, DEFAULT_BACKPRESSURE_BYTES, DEFAULT_COALESCE_BYTES);
+        queue.enqueue(vec![1, 2, 3]).unwrap();
+
+        let mut dst = WouldBlockWriter;
+        let remaining = queue.try_flush(&mut dst).unwrap();
+
+        assert_eq!(remaining, 3);
+        assert!(!queue.is_empty());
+    }
+}
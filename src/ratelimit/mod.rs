@@ -0,0 +1,299 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the
+// terms of the Mozilla Public License, v.
+// 2.0. If a copy of the MPL was not
+// distributed with this file, You can
+// obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+//! Ratelimit module.
+//! Wraps any `Blocking`/`NonBlocking` stream with a token-bucket
+//! bandwidth cap, enforced separately for reads and writes.
+
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {Blocking, NonBlocking};
+
+
+/// A classic token bucket: `capacity` bytes refill at `refill_rate`
+/// bytes/sec, up to `capacity`. A `refill_rate` of `0` means unlimited.
+struct TokenBucket {
+    capacity: f64,
+    refill_rate: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+
+    fn new(capacity: usize, refill_rate: usize) -> TokenBucket {
+        TokenBucket {
+            capacity: capacity as f64,
+            refill_rate: refill_rate as f64,
+            tokens: capacity as f64,
+            last_refill: Instant::now()
+        }
+    }
+
+    fn refill(&mut self) {
+        if self.refill_rate == 0.0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64
+            + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+
+        self.tokens = (self.tokens + elapsed_secs * self.refill_rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Blocks, if necessary, until `bytes` tokens are available, then
+    /// consumes them. A `refill_rate` of `0` never blocks.
+    fn acquire_blocking(&mut self, bytes: usize) {
+        if self.refill_rate == 0.0 {
+            return;
+        }
+
+        self.refill();
+
+        let needed = bytes as f64;
+        if needed > self.tokens {
+            let deficit = needed - self.tokens;
+            let wait_secs = deficit / self.refill_rate;
+            let whole_secs = wait_secs.floor() as u64;
+            let nanos = ((wait_secs - whole_secs as f64) * 1_000_000_000.0) as u32;
+
+            thread::sleep(Duration::new(whole_secs, nanos));
+            self.refill();
+        }
+
+        self.tokens -= needed;
+    }
+
+    /// Consumes exactly `bytes` tokens if available, leaving the bucket
+    /// untouched otherwise. Used where a partial grant is meaningless,
+    /// such as an atomic message that can't be sent piecemeal.
+    fn try_acquire_exact(&mut self, bytes: usize) -> bool {
+        if self.refill_rate == 0.0 {
+            return true;
+        }
+
+        self.refill();
+
+        if bytes as f64 > self.tokens {
+            return false;
+        }
+
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+
+/// Default cap, in bytes, on how much `pending_recv` may hold before the
+/// underlying stream stops being drained. Without this, a peer that
+/// sends faster than the configured read rate grows that buffer without
+/// bound: the reported byte rate is capped, but the memory cost of an
+/// unmetered sender is merely deferred rather than prevented.
+pub const DEFAULT_MAX_PENDING_RECV_BYTES: usize = 1024 * 1024;
+
+
+/// Wraps a stream with separate read and write token buckets, throttling
+/// throughput in each direction independently. Because this crate's
+/// streams hand back whole messages rather than raw bytes, rate limiting
+/// is applied per message: a message is only let through once enough
+/// tokens exist to cover its full size.
+pub struct RateLimited<S> {
+    stream: S,
+    read_bucket: TokenBucket,
+    write_bucket: TokenBucket,
+    /// Messages already pulled off the underlying non-blocking stream
+    /// but held back pending read-rate tokens
+    pending_recv: VecDeque<Vec<u8>>,
+    /// Cap, in bytes, on `pending_recv`. Once reached, the underlying
+    /// stream stops being drained until tokens free some of it up again,
+    /// leaving the backlog in the kernel's socket buffer instead.
+    max_pending_recv_bytes: usize
+}
+
+impl<S> RateLimited<S> {
+
+    /// Wraps `stream`, capping reads at `read_bps` bytes/sec and writes
+    /// at `write_bps` bytes/sec, each with up to `burst` bytes of slack.
+    /// A rate of `0` means unlimited in that direction. `pending_recv`
+    /// is capped at `DEFAULT_MAX_PENDING_RECV_BYTES`.
+    pub fn new(stream: S, read_bps: usize, write_bps: usize, burst: usize) -> RateLimited<S> {
+        RateLimited::with_max_pending_recv_bytes(
+            stream, read_bps, write_bps, burst, DEFAULT_MAX_PENDING_RECV_BYTES)
+    }
+
+    /// Same as `new`, with an explicit cap on how many bytes `nb_recv`
+    /// may buffer in `pending_recv` before it stops draining the
+    /// underlying stream
+    pub fn with_max_pending_recv_bytes(stream: S, read_bps: usize, write_bps: usize,
+                                        burst: usize, max_pending_recv_bytes: usize) -> RateLimited<S> {
+        RateLimited {
+            stream: stream,
+            read_bucket: TokenBucket::new(burst, read_bps),
+            write_bucket: TokenBucket::new(burst, write_bps),
+            pending_recv: VecDeque::new(),
+            max_pending_recv_bytes: max_pending_recv_bytes
+        }
+    }
+}
+
+impl<S: Blocking> Blocking for RateLimited<S> {
+
+    /// Receives a message, then sleeps as needed so the read rate stays
+    /// under the configured cap
+    fn b_recv(&mut self) -> Result<Vec<u8>, Error> {
+        let msg = match self.stream.b_recv() {
+            Ok(msg) => msg,
+            Err(e) => return Err(e)
+        };
+
+        self.read_bucket.acquire_blocking(msg.len());
+        Ok(msg)
+    }
+
+    /// Sleeps as needed so the write rate stays under the configured
+    /// cap, then sends `buf`
+    fn b_send(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.write_bucket.acquire_blocking(buf.len());
+        self.stream.b_send(buf)
+    }
+}
+
+impl<S: NonBlocking> NonBlocking for RateLimited<S> {
+
+    /// Pulls whatever the underlying stream has ready, then releases
+    /// only as many of those messages as the read rate currently allows.
+    /// Messages held back stay queued for the next call instead of
+    /// being dropped. Once `pending_recv` holds `max_pending_recv_bytes`
+    /// or more, the underlying stream is left undrained until the read
+    /// rate frees some of it up, so a sender outrunning the configured
+    /// rate backs up in the kernel's socket buffer instead of here.
+    fn nb_recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let pending_bytes: usize = self.pending_recv.iter().map(|msg| msg.len()).sum();
+
+        if pending_bytes < self.max_pending_recv_bytes {
+            match self.stream.nb_recv() {
+                Ok(msgs) => self.pending_recv.extend(msgs),
+                Err(e) => return Err(e)
+            }
+        }
+
+        let mut allowed = Vec::new();
+        loop {
+            let fits = match self.pending_recv.front() {
+                Some(msg) => self.read_bucket.try_acquire_exact(msg.len()),
+                None => break
+            };
+
+            if !fits {
+                break;
+            }
+
+            allowed.push(self.pending_recv.pop_front().unwrap());
+        }
+
+        Ok(allowed)
+    }
+
+    /// Sends `buf` only if the write rate currently has enough tokens
+    /// for its full size; otherwise reports `WouldBlock` without
+    /// touching the underlying stream, so the caller retries later.
+    fn nb_send(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if !self.write_bucket.try_acquire_exact(buf.len()) {
+            return Err(Error::new(ErrorKind::WouldBlock, "write rate limit exceeded"));
+        }
+
+        self.stream.nb_send(buf)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockStream {
+        to_return: VecDeque<Vec<Vec<u8>>>,
+        recv_calls: usize
+    }
+
+    impl MockStream {
+        fn new() -> MockStream {
+            MockStream { to_return: VecDeque::new(), recv_calls: 0 }
+        }
+
+        fn push_batch(&mut self, batch: Vec<Vec<u8>>) {
+            self.to_return.push_back(batch);
+        }
+    }
+
+    impl NonBlocking for MockStream {
+        fn nb_recv(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+            self.recv_calls += 1;
+            Ok(self.to_return.pop_front().unwrap_or_else(Vec::new))
+        }
+
+        fn nb_send(&mut self, _buf: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn try_acquire_exact_leaves_bucket_untouched_on_partial_grant() {
+        let mut bucket = TokenBucket::new(10, 1);
+
+        assert!(bucket.try_acquire_exact(6));
+        assert!(!bucket.try_acquire_exact(10));
+        assert!(bucket.try_acquire_exact(4));
+        assert!(!bucket.try_acquire_exact(1));
+    }
+
+    #[test]
+    fn acquire_blocking_waits_for_missing_tokens() {
+        let mut bucket = TokenBucket::new(10, 1_000_000);
+        assert!(bucket.try_acquire_exact(10));
+
+        let start = Instant::now();
+        bucket.acquire_blocking(1);
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn nb_recv_stops_draining_underlying_stream_once_pending_cap_reached() {
+        let mut stream = MockStream::new();
+        stream.push_batch(vec![vec![0u8; 10]]);
+        stream.push_batch(vec![vec![0u8; 10]]);
+
+        let mut limited = RateLimited::with_max_pending_recv_bytes(stream, 1, 0, 0, 10);
+
+        let first = limited.nb_recv().unwrap();
+        assert!(first.is_empty());
+
+        let second = limited.nb_recv().unwrap();
+        assert!(second.is_empty());
+
+        assert_eq!(limited.stream.recv_calls, 1);
+    }
+
+    #[test]
+    fn nb_send_rejects_when_write_rate_exhausted() {
+        let stream = MockStream::new();
+        let mut limited = RateLimited::new(stream, 0, 1, 0);
+
+        let result = limited.nb_send(&[1, 2, 3]);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+
+//! Accumulates bytes off the wire into length-prefixed messages,
+//! according to a configurable `FrameLen`.
+
+
+use std::io::{Error, ErrorKind};
+
+use frame::FrameLen;
+
+
+/// Maximum number of header bytes a `Varint`-framed stream will
+/// accumulate before giving up. Ten bytes covers a full `u64` worth of
+/// 7-bit groups; a continuation bit set on every byte past that can only
+/// come from a malformed or hostile peer, since no real length needs
+/// more.
+const MAX_VARINT_HEADER_BYTES: usize = 10;
+
+
+/// Holds the in-progress header or payload for a stream, plus any
+/// completed messages waiting to be drained
+#[derive(Clone)]
+pub struct ReadBuffer {
+    /// Header width/encoding this buffer was configured with
+    frame_len: FrameLen,
+    /// Bytes read so far for the current phase (header or payload)
+    buf: Vec<u8>,
+    /// Number of bytes `buf` needs to hold before the current phase
+    /// is complete
+    target: usize,
+    /// Decoded length of the payload currently being read
+    payload_len: usize,
+    /// Completed messages, ready to be drained
+    queue: Vec<Vec<u8>>
+}
+
+impl ReadBuffer {
+
+    /// Returns a new ReadBuffer configured to read headers of the given
+    /// `FrameLen`
+    pub fn new(frame_len: FrameLen) -> ReadBuffer {
+        ReadBuffer {
+            target: frame_len.min_header_len(),
+            frame_len: frame_len,
+            buf: Vec::new(),
+            payload_len: 0,
+            queue: Vec::new()
+        }
+    }
+
+    /// Number of bytes still needed before the current phase (header or
+    /// payload) is complete
+    pub fn remaining(&self) -> usize {
+        self.target - self.buf.len()
+    }
+
+    /// Pushes a single byte onto the in-progress buffer. While reading a
+    /// `Varint` header, extends the target by one more byte for every
+    /// continuation byte encountered, erroring out once the header grows
+    /// past `MAX_VARINT_HEADER_BYTES` rather than growing forever.
+    pub fn push(&mut self, byte: u8) -> Result<(), Error> {
+        self.buf.push(byte);
+
+        let reading_header = self.payload_len == 0;
+        if self.frame_len == FrameLen::Varint
+            && reading_header
+            && self.buf.len() == self.target
+            && FrameLen::has_continuation(byte)
+        {
+            if self.target >= MAX_VARINT_HEADER_BYTES {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("varint header exceeds {} bytes", MAX_VARINT_HEADER_BYTES)));
+            }
+
+            self.target += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Bytes accumulated so far for the current phase
+    pub fn current_buffer(&self) -> &Vec<u8> {
+        &self.buf
+    }
+
+    /// Decodes the header currently in the buffer into a payload length
+    pub fn calc_payload_len(&mut self) {
+        self.payload_len = self.frame_len.decode(&self.buf);
+    }
+
+    /// Decoded length of the payload currently being read
+    pub fn payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    /// Switches the buffer over to collecting `len` bytes of payload
+    pub fn set_capacity(&mut self, len: usize) {
+        self.buf.clear();
+        self.target = len;
+    }
+
+    /// Pushes the completed payload onto the output queue and resets the
+    /// buffer to await the next header
+    pub fn reset(&mut self) {
+        self.queue.push(self.buf.clone());
+        self.buf.clear();
+        self.payload_len = 0;
+        self.target = self.frame_len.min_header_len();
+    }
+
+    /// Drains and returns all completed messages
+    pub fn drain_queue(&mut self) -> Vec<Vec<u8>> {
+        self.queue.drain(..).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_header_rejects_unterminated_continuation_run() {
+        let mut buf = ReadBuffer::new(FrameLen::Varint);
+
+        for _ in 0..(MAX_VARINT_HEADER_BYTES - 1) {
+            assert!(buf.push(0xff).is_ok());
+        }
+
+        assert!(buf.push(0xff).is_err());
+    }
+
+    #[test]
+    fn varint_header_accepts_terminated_header_within_limit() {
+        let mut buf = ReadBuffer::new(FrameLen::Varint);
+
+        for _ in 0..(MAX_VARINT_HEADER_BYTES - 1) {
+            assert!(buf.push(0xff).is_ok());
+        }
+
+        assert!(buf.push(0x01).is_ok());
+        assert_eq!(buf.remaining(), 0);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+
+//! Read-side state machine. Drives any `Read` through a `Codec` until a
+//! complete message falls out.
+
+
+use std::io::{Read, Error, ErrorKind};
+
+use frame::Codec;
+use readbuffer::ReadBuffer;
+
+
+/// Accumulates bytes from a `Read` and hands them to a `Codec` until a
+/// complete message has been decoded
+pub struct Reader<C: Codec> {
+    codec: C,
+    buffer: ReadBuffer
+}
+
+impl<C: Codec> Reader<C> {
+
+    /// Returns a new Reader driven by `codec`
+    pub fn new(codec: C) -> Reader<C> {
+        let buffer = codec.new_buffer();
+        Reader {
+            codec: codec,
+            buffer: buffer
+        }
+    }
+
+    /// Reads from `src` until `codec` produces a complete message
+    pub fn read<R: Read>(&mut self, src: &mut R) -> Result<Vec<u8>, Error> {
+        loop {
+            let count = self.buffer.remaining();
+
+            // A zero-length payload (or any phase with nothing left to
+            // read) needs no syscall: a 0-byte `read` on a blocking
+            // stream blocks waiting for bytes that will never come,
+            // rather than returning `Ok(0)`. Let the codec finish the
+            // phase directly instead.
+            if count == 0 {
+                match self.codec.decode(&mut self.buffer) {
+                    Ok(Some(msg)) => return Ok(msg),
+                    Ok(None) => continue,
+                    Err(e) => return Err(e)
+                }
+            }
+
+            let mut chunk = Vec::<u8>::with_capacity(count);
+            unsafe { chunk.set_len(count); }
+
+            let result = src.read(&mut chunk[..]);
+            if result.is_err() {
+                return Err(result.unwrap_err());
+            }
+
+            let num_read = result.unwrap();
+            if num_read == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "stream closed mid-frame"));
+            }
+
+            for x in 0..num_read {
+                match self.buffer.push(chunk[x]) {
+                    Ok(()) => {}
+                    Err(e) => return Err(e)
+                }
+            }
+
+            match self.codec.decode(&mut self.buffer) {
+                Ok(Some(msg)) => return Ok(msg),
+                Ok(None) => {}
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+impl<C: Codec + Clone> Clone for Reader<C> {
+    fn clone(&self) -> Reader<C> {
+        Reader {
+            codec: self.codec.clone(),
+            buffer: self.buffer.clone()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::Reader;
+    use frame::{Codec, FrameLen, LengthPrefixCodec};
+
+    #[test]
+    fn reads_zero_length_payload_without_blocking() {
+        let codec = LengthPrefixCodec::new(FrameLen::U16);
+        let mut reader = Reader::new(codec);
+
+        let mut wire = codec.encode(&[]);
+        wire.extend_from_slice(&codec.encode(b"after"));
+        let mut src = Cursor::new(wire);
+
+        let first = reader.read(&mut src).expect("zero-length read should not error");
+        assert_eq!(first, Vec::<u8>::new());
+
+        let second = reader.read(&mut src).expect("next message should still be readable");
+        assert_eq!(second, b"after".to_vec());
+    }
+}
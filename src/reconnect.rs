@@ -0,0 +1,133 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+
+//! Defines the retry policy streams use to transparently re-establish a
+//! dropped `TcpStream`, following the resync approach used by revpfw3.
+
+
+use std::net::{SocketAddr, TcpStream};
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+
+/// How a stream should behave when its underlying connection drops:
+/// how many times to retry connecting to the original target, and how
+/// long to wait between attempts.
+#[derive(Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Maximum number of reconnect attempts before giving up
+    pub max_retries: usize,
+    /// Delay between reconnect attempts
+    pub backoff: Duration
+}
+
+impl ReconnectPolicy {
+
+    /// Returns a new ReconnectPolicy
+    pub fn new(max_retries: usize, backoff: Duration) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_retries: max_retries,
+            backoff: backoff
+        }
+    }
+}
+
+/// Returns true if `err` indicates the peer is gone rather than some
+/// other, non-recoverable I/O failure
+pub fn is_peer_gone(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::NotConnected => true,
+        _ => false
+    }
+}
+
+
+/// Bundles a stream's original connect target with its (optional)
+/// reconnect policy, and drives the retry loop both `Bstream` and
+/// `Nbstream` need when their connection drops. Keeping this in one
+/// place means the retry/backoff timing can't drift between the two.
+#[derive(Clone, Copy)]
+pub struct ReconnectState {
+    /// Original target, used to re-establish the connection. `None` if
+    /// the peer address couldn't be read at construction time, in which
+    /// case reconnect is simply unavailable.
+    connect_addr: Option<SocketAddr>,
+    /// If set, a dropped connection is transparently re-established
+    /// instead of surfacing the error to the caller
+    policy: Option<ReconnectPolicy>
+}
+
+impl ReconnectState {
+
+    /// Returns a new ReconnectState targeting `connect_addr`, with
+    /// reconnect disabled until `set_policy` is called
+    pub fn new(connect_addr: Option<SocketAddr>) -> ReconnectState {
+        ReconnectState {
+            connect_addr: connect_addr,
+            policy: None
+        }
+    }
+
+    /// Enables transparent reconnection according to `policy`
+    pub fn set_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// True if `err` indicates the connection is gone and a reconnect
+    /// policy has been configured
+    pub fn should_reconnect(&self, err: &Error) -> bool {
+        self.policy.is_some() && is_peer_gone(err)
+    }
+
+    /// Attempts to re-establish `connect_addr`, retrying according to
+    /// the configured policy: connects immediately, then only sleeps
+    /// `policy.backoff` between subsequent attempts, up to
+    /// `policy.max_retries` attempts total (a single attempt when
+    /// `max_retries` is `0`). Callers are responsible for
+    /// swapping the returned stream in and rebuilding anything keyed off
+    /// the old connection (decode state, non-blocking mode, and so on).
+    pub fn reconnect(&self) -> Result<TcpStream, Error> {
+        let policy = match self.policy {
+            Some(policy) => policy,
+            None => return Err(Error::new(
+                ErrorKind::NotConnected,
+                "connection lost and no reconnect policy configured"))
+        };
+
+        let connect_addr = match self.connect_addr {
+            Some(addr) => addr,
+            None => return Err(Error::new(
+                ErrorKind::NotConnected,
+                "no connect target available for reconnect"))
+        };
+
+        let mut attempt = 0;
+
+        let last_err = loop {
+            let err = match TcpStream::connect(connect_addr) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e
+            };
+
+            attempt += 1;
+            if attempt >= policy.max_retries {
+                break err;
+            }
+
+            thread::sleep(policy.backoff);
+        };
+
+        Err(last_err)
+    }
+}
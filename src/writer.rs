@@ -0,0 +1,48 @@
+// Copyright 2015 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not
+// distributed with this file, You can obtain one at
+// http://mozilla.org/MPL/2.0/.
+
+
+
+//! Write-side state machine. Encodes a message with a `Codec` and
+//! writes the result to any `Write`.
+
+
+use std::io::{Write, Error};
+
+use frame::Codec;
+
+
+/// Encodes messages with a `Codec` and writes them to a `Write`
+pub struct Writer<C: Codec> {
+    codec: C
+}
+
+impl<C: Codec> Writer<C> {
+
+    /// Returns a new Writer driven by `codec`
+    pub fn new(codec: C) -> Writer<C> {
+        Writer { codec: codec }
+    }
+
+    /// Encodes `msg` and writes the result to `dst`
+    pub fn write<W: Write>(&self, dst: &mut W, msg: &[u8]) -> Result<(), Error> {
+        let encoded = self.codec.encode(msg);
+        match dst.write_all(&encoded[..]) {
+            Ok(()) => {
+                let _ = dst.flush();
+                Ok(())
+            }
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl<C: Codec + Clone> Clone for Writer<C> {
+    fn clone(&self) -> Writer<C> {
+        Writer { codec: self.codec.clone() }
+    }
+}